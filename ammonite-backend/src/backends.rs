@@ -0,0 +1,219 @@
+use axum::body::{Body, Bytes, HttpBody};
+use axum::http::HeaderMap;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Live state for a single backend: how many requests are currently in
+/// flight against it, and whether the last health probe succeeded.
+#[derive(Debug)]
+pub struct Backend {
+    pub addr: SocketAddr,
+    in_flight: AtomicUsize,
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    fn new(addr: SocketAddr) -> Self {
+        Backend {
+            addr,
+            in_flight: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy
+            .store(healthy, std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// Held for the lifetime of a proxied request; decrements the chosen
+/// backend's in-flight count on drop so it covers both the success and
+/// error paths.
+pub struct InFlightGuard {
+    backend: Arc<Backend>,
+}
+
+impl InFlightGuard {
+    pub fn addr(&self) -> SocketAddr {
+        self.backend.addr
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.backend
+            .in_flight
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Wraps a response body so the in-flight guard for the backend that served
+/// it is dropped only once the body finishes streaming to the client (or
+/// the connection is torn down early), not as soon as the handler that
+/// produced the response headers returns. Mastodon's streaming API can hold
+/// a response open indefinitely; without this, a backend serving an open
+/// stream looks idle to `BackendPool::choose` almost immediately, and
+/// power-of-two-choices keeps piling more traffic onto it.
+pub struct TrackedBody {
+    inner: Body,
+    _guard: InFlightGuard,
+}
+
+impl TrackedBody {
+    pub fn new(inner: Body, guard: InFlightGuard) -> Self {
+        TrackedBody {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// The set of configured upstream backends, load balanced over with
+/// power-of-two-random-choices.
+#[derive(Clone)]
+pub struct BackendPool {
+    backends: Arc<Vec<Arc<Backend>>>,
+    round_robin: Arc<AtomicUsize>,
+}
+
+impl BackendPool {
+    pub fn new(addrs: Vec<SocketAddr>) -> Self {
+        BackendPool {
+            backends: Arc::new(addrs.into_iter().map(Backend::new).map(Arc::new).collect()),
+            round_robin: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    /// Samples two distinct healthy backends uniformly at random and
+    /// returns the one with fewer in-flight requests, breaking ties with
+    /// round-robin. Returns `None` if no backend is currently healthy.
+    pub fn choose(&self) -> Option<InFlightGuard> {
+        let healthy: Vec<&Arc<Backend>> = self.backends.iter().filter(|b| b.is_healthy()).collect();
+
+        let chosen = match healthy.len() {
+            0 => return None,
+            1 => healthy[0],
+            len => {
+                let mut rng = rand::thread_rng();
+                let i = rng.gen_range(0..len);
+                let mut j = rng.gen_range(0..len - 1);
+                if j >= i {
+                    j += 1;
+                }
+
+                match healthy[i].in_flight().cmp(&healthy[j].in_flight()) {
+                    Ordering::Less => healthy[i],
+                    Ordering::Greater => healthy[j],
+                    Ordering::Equal => {
+                        let idx = self
+                            .round_robin
+                            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                            % len;
+                        healthy[idx]
+                    }
+                }
+            }
+        };
+
+        chosen
+            .in_flight
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        Some(InFlightGuard {
+            backend: chosen.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{port}").parse().unwrap()
+    }
+
+    #[test]
+    fn choose_returns_none_when_all_unhealthy() {
+        let pool = BackendPool::new(vec![addr(1), addr(2)]);
+
+        for backend in pool.backends() {
+            backend.set_healthy(false);
+        }
+
+        assert!(pool.choose().is_none());
+    }
+
+    #[test]
+    fn choose_always_picks_the_only_healthy_backend() {
+        let pool = BackendPool::new(vec![addr(1), addr(2), addr(3)]);
+        pool.backends()[0].set_healthy(false);
+        pool.backends()[2].set_healthy(false);
+
+        for _ in 0..10 {
+            let guard = pool.choose().unwrap();
+            assert_eq!(guard.addr(), addr(2));
+        }
+    }
+
+    #[test]
+    fn choose_round_robins_on_ties() {
+        let pool = BackendPool::new(vec![addr(1), addr(2)]);
+
+        let mut chosen = Vec::new();
+        for _ in 0..4 {
+            // Drop each guard before the next choose() so every call starts
+            // from an equal in-flight count and hits the tie-break path.
+            chosen.push(pool.choose().unwrap().addr());
+        }
+
+        assert_eq!(chosen, vec![addr(1), addr(2), addr(1), addr(2)]);
+    }
+}
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::net::SocketAddr;
 
 #[derive(Parser, Debug, Clone)]
@@ -6,8 +6,66 @@ use std::net::SocketAddr;
 pub struct Args {
     #[arg(short, long)]
     pub bind: SocketAddr,
-    #[arg(short, long)]
-    pub remote: SocketAddr,
+    /// Backend to proxy to. Repeat to spread traffic across several
+    /// backends via power-of-two-random-choices load balancing.
+    #[arg(short, long, required = true)]
+    pub remote: Vec<SocketAddr>,
     #[arg(short, long)]
     pub metrics: SocketAddr,
+    /// Prepend a PROXY protocol header on the upstream connection so the
+    /// backend can recover the original client address, in addition to the
+    /// `X-Forwarded-*`/`Forwarded` headers which are always injected.
+    #[arg(long, value_enum, default_value_t = ProxyProtocol::Off)]
+    pub proxy_protocol: ProxyProtocol,
+    /// Scheme to use when connecting to the upstream backend.
+    #[arg(long, value_enum, default_value_t = UpstreamScheme::Http)]
+    pub upstream_scheme: UpstreamScheme,
+    /// Speak HTTP/2 to a plain `http` upstream backend with prior knowledge
+    /// (h2c), since there is no TLS handshake to negotiate during. Has no
+    /// effect with `--upstream-scheme https`: there h2 is always advertised
+    /// via ALPN alongside http/1.1 and the backend's choice is used.
+    #[arg(long)]
+    pub upstream_http2: bool,
+    /// OTLP gRPC endpoint to export traces to, e.g. `http://localhost:4317`.
+    /// When unset, tracing stays local to the `tracing_subscriber` formatter.
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` resource attribute reported on exported spans.
+    #[arg(long, default_value = "mastodon")]
+    pub service_name: String,
+    /// Seconds between backend health probes.
+    #[arg(long, default_value_t = 5)]
+    pub health_check_interval_secs: u64,
+    /// Milliseconds to wait for a backend health probe before treating it
+    /// as failed.
+    #[arg(long, default_value_t = 500)]
+    pub health_check_timeout_millis: u64,
+    /// Seconds to let in-flight requests finish after a SIGINT/SIGTERM
+    /// before forcibly closing remaining connections.
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_grace_secs: u64,
+    /// Largest request body, in bytes, accepted from a client that sends
+    /// `Expect: 100-continue` (checked against `Content-Length` before the
+    /// body is read). Defaults to 100 MiB, Mastodon's default media upload
+    /// limit.
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    pub max_body_bytes: u64,
+    /// Verify HTTP Signatures on inbound ActivityPub deliveries to `/inbox`
+    /// and `/users/*/inbox` before forwarding them upstream. Off by default
+    /// so plain reverse-proxy use is unaffected.
+    #[arg(long)]
+    pub verify_signatures: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    Off,
+    V1,
+    V2,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamScheme {
+    Http,
+    Https,
 }
@@ -0,0 +1,37 @@
+use crate::backends::BackendPool;
+use metrics::gauge;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// Periodically probes every configured backend with a bounded TCP connect
+/// attempt, marking failures unhealthy so `BackendPool::choose` skips them
+/// until they recover, and reports per-backend health and in-flight gauges.
+pub async fn run(pool: BackendPool, interval: Duration, probe_timeout: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        for backend in pool.backends() {
+            let addr = backend.addr;
+
+            let healthy = matches!(timeout(probe_timeout, TcpStream::connect(addr)).await, Ok(Ok(_)));
+
+            if healthy != backend.is_healthy() {
+                tracing::warn!(message = "Backend health changed", %addr, healthy);
+            }
+
+            backend.set_healthy(healthy);
+
+            gauge!(
+                "backend_healthy", if healthy { 1.0 } else { 0.0 },
+                "backend" => addr.to_string()
+            );
+            gauge!(
+                "backend_in_flight_requests", backend.in_flight() as f64,
+                "backend" => addr.to_string()
+            );
+        }
+    }
+}
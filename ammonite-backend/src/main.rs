@@ -1,46 +1,112 @@
-use crate::cli::Args;
+use crate::backends::{BackendPool, InFlightGuard, TrackedBody};
+use crate::cli::{Args, ProxyProtocol, UpstreamScheme};
+use crate::signatures::{verify_signatures, KeyCache};
 use crate::state::ProxyState;
 use axum::body::Body;
-use axum::extract::{Host, State};
-use axum::http::Request;
-use axum::middleware::{from_fn, Next};
+use axum::extract::{ConnectInfo, Host, State};
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::middleware::{from_fn, from_fn_with_state, Next};
 use axum::response::Response;
 use axum::Router;
 use clap::Parser;
-use hyper::client::connect::dns::Name;
+use hyper::client::conn::Builder as ConnBuilder;
 use hyper::client::HttpConnector;
+use hyper::header::{self, HOST};
 use hyper::{Client, Server, StatusCode, Uri};
+use hyper_rustls::HttpsConnectorBuilder;
 use metrics::{counter, histogram};
 use metrics_exporter_prometheus::PrometheusBuilder;
-use std::convert::Infallible;
-use std::future::{ready, Ready};
-use std::iter::{once, Once};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
 use std::net::SocketAddr;
-use std::task::{Context, Poll};
-use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::signal::unix::SignalKind;
 use tower::Service;
-use tracing::level_filters::LevelFilter;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Number of requests whose handler has not yet produced response headers.
+/// A response stops being counted once its headers are returned, so a
+/// long-lived streaming response whose body is still being sent is not
+/// included. Sampled during graceful shutdown to report how many requests
+/// were still being handled when draining began and when the grace period
+/// elapsed.
+static ACTIVE_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// How long a fetched actor public key is trusted before being refetched.
+const KEY_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+mod backends;
 
 mod cli;
 
+mod health;
+
+mod proxy_protocol;
+
+mod signatures;
+
 mod state;
 
+mod telemetry;
+
 async fn fallback(
     Host(hostname): Host,
     State(state): State<ProxyState>,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
     mut request: Request<Body>,
-) -> Response<Body> {
+) -> Response {
+    let Some(backend) = state.backends.choose() else {
+        tracing::error!(message = "No healthy backend available to serve request");
+
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap()
+            .map(axum::body::boxed);
+    };
+
+    let backend_addr = backend.addr();
+
     let uri = request.uri().clone();
 
+    let scheme = match state.upstream_scheme {
+        UpstreamScheme::Http => "http",
+        UpstreamScheme::Https => "https",
+    };
+
     *request.uri_mut() = Uri::builder()
-        .authority(format!("{hostname}:{}", state.remote.port()))
-        .scheme("http")
+        .authority(backend_addr.to_string())
+        .scheme(scheme)
         .path_and_query(uri.path_and_query().unwrap().clone())
         .build()
         .unwrap();
 
+    // The URI authority above now names the chosen backend, not the vhost
+    // the client asked for, so the `Host` header has to be set explicitly
+    // to keep upstream vhost routing working.
+    if let Ok(value) = HeaderValue::from_str(&hostname) {
+        request.headers_mut().insert(HOST, value);
+    }
+
+    insert_forwarding_headers(request.headers_mut(), peer_addr, scheme);
+
+    if let Some(response) = check_expect_continue(&request, state.max_body_bytes) {
+        return response.map(axum::body::boxed);
+    }
+
+    let cx = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(request.headers_mut()));
+    });
+
+    if state.proxy_protocol != ProxyProtocol::Off {
+        return send_with_proxy_protocol(&state, peer_addr, backend_addr, request, backend).await;
+    }
+
     match state.client.request(request).await {
-        Ok(r) => r,
+        Ok(response) => response.map(|body| axum::body::boxed(TrackedBody::new(body, backend))),
         Err(error) => {
             tracing::error!(message = "Internal error when talking to upstream", ?error);
 
@@ -48,6 +114,148 @@ async fn fallback(
                 .status(StatusCode::INTERNAL_SERVER_ERROR)
                 .body(Body::empty())
                 .unwrap()
+                .map(axum::body::boxed)
+        }
+    }
+}
+
+/// Stamps the request with `X-Forwarded-For`, `X-Forwarded-Proto` and
+/// `Forwarded` headers derived from the real client address, since upstream
+/// otherwise only ever sees connections originating from this proxy.
+fn insert_forwarding_headers(headers: &mut HeaderMap, peer_addr: SocketAddr, scheme: &str) {
+    let client_ip = peer_addr.ip().to_string();
+
+    if let Ok(value) = HeaderValue::from_str(&client_ip) {
+        headers.insert("x-forwarded-for", value);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(scheme) {
+        headers.insert("x-forwarded-proto", value);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&format!("for={client_ip}")) {
+        headers.insert("forwarded", value);
+    }
+}
+
+/// Rejects an inbound `Expect: 100-continue` request before its body is
+/// ever read, rather than relying on the upstream to reject a body the
+/// proxy was always going to refuse. Returns `Some` response to send back
+/// to the client when the request must be rejected outright; otherwise the
+/// caller forwards the request and hyper relays the upstream's own interim
+/// `100 Continue` response unchanged.
+fn check_expect_continue(request: &Request<Body>, max_body_bytes: u64) -> Option<Response<Body>> {
+    let expect = request.headers().get(header::EXPECT)?;
+
+    if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+        counter!("expect_continue_failed", 1);
+
+        return Some(
+            Response::builder()
+                .status(StatusCode::EXPECTATION_FAILED)
+                .body(Body::empty())
+                .unwrap(),
+        );
+    }
+
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if let Some(len) = content_length {
+        if len > max_body_bytes {
+            counter!("expect_continue_rejected_too_large", 1);
+
+            return Some(
+                Response::builder()
+                    .status(StatusCode::PAYLOAD_TOO_LARGE)
+                    .body(Body::empty())
+                    .unwrap(),
+            );
+        }
+    }
+
+    counter!("expect_continue_accepted", 1);
+    None
+}
+
+/// Opens a fresh connection to `dst`, writes the configured PROXY protocol
+/// header, and then performs the HTTP handshake by hand. hyper's pooled
+/// `Client` hides the raw socket, so PROXY protocol support requires
+/// bypassing it and driving a single-use connection with `hyper::client::conn`,
+/// honoring `--upstream-http2` the same way the pooled client does.
+async fn send_with_proxy_protocol(
+    state: &ProxyState,
+    peer_addr: SocketAddr,
+    dst: SocketAddr,
+    request: Request<Body>,
+    backend: InFlightGuard,
+) -> Response {
+    let mut connector = state.connector.clone();
+
+    let mut stream = match connector.call(request.uri().clone()).await {
+        Ok(stream) => stream,
+        Err(error) => {
+            tracing::error!(message = "Failed to connect to upstream", ?error);
+
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::empty())
+                .unwrap()
+                .map(axum::body::boxed);
+        }
+    };
+
+    let header = match state.proxy_protocol {
+        ProxyProtocol::V1 => proxy_protocol::encode_v1(peer_addr, dst),
+        ProxyProtocol::V2 => proxy_protocol::encode_v2(peer_addr, dst),
+        ProxyProtocol::Off => Vec::new(),
+    };
+
+    if let Err(error) = stream.write_all(&header).await {
+        tracing::error!(message = "Failed to write PROXY protocol header", ?error);
+
+        return Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::empty())
+            .unwrap()
+            .map(axum::body::boxed);
+    }
+
+    let mut conn_builder = ConnBuilder::new();
+    conn_builder.http2_only(state.upstream_http2);
+
+    let (mut sender, connection) = match conn_builder.handshake(stream).await {
+        Ok(pair) => pair,
+        Err(error) => {
+            tracing::error!(message = "Failed to handshake with upstream", ?error);
+
+            return Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::empty())
+                .unwrap()
+                .map(axum::body::boxed);
+        }
+    };
+
+    tokio::spawn(async move {
+        if let Err(error) = connection.await {
+            tracing::error!(message = "Upstream connection driver failed", ?error);
+        }
+    });
+
+    match sender.send_request(request).await {
+        Ok(response) => response.map(|body| axum::body::boxed(TrackedBody::new(body, backend))),
+        Err(error) => {
+            tracing::error!(message = "Internal error when talking to upstream", ?error);
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap()
+                .map(axum::body::boxed)
         }
     }
 }
@@ -56,9 +264,23 @@ async fn observe<B>(request: Request<B>, next: Next<B>) -> Response {
     let uri = request.uri().clone();
     let method = request.method().clone();
 
+    let span = tracing::info_span!(
+        "http_request",
+        "otel.kind" = "server",
+        "http.method" = %method,
+        "http.url" = %uri,
+    );
+
+    let parent_cx = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent_cx);
+
     let start = Instant::now();
 
-    let response = next.run(request).await;
+    ACTIVE_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    let response = next.run(request).instrument(span).await;
+    ACTIVE_REQUESTS.fetch_sub(1, Ordering::Relaxed);
 
     let delta = start.elapsed();
 
@@ -121,12 +343,23 @@ async fn observe<B>(request: Request<B>, next: Next<B>) -> Response {
 async fn main() {
     let args = Args::parse();
 
-    tracing_subscriber::fmt::fmt()
-        .with_max_level(LevelFilter::INFO)
-        .init();
+    // `send_with_proxy_protocol` writes the PROXY header directly onto the
+    // raw stream before handing it to hyper, which only ever speaks plain
+    // HTTP on that path; it has no TLS handshake step to fit the header
+    // before. Reject the combination up front rather than silently sending
+    // plaintext to what's presumably a TLS-only backend port.
+    if args.proxy_protocol != ProxyProtocol::Off && args.upstream_scheme == UpstreamScheme::Https {
+        eprintln!(
+            "--proxy-protocol {{v1,v2}} is not supported together with --upstream-scheme https: \
+             the PROXY protocol path speaks plain HTTP only"
+        );
+        std::process::exit(1);
+    }
+
+    telemetry::init(&args);
 
     PrometheusBuilder::new()
-        .add_global_label("service", "mastodon")
+        .add_global_label("service", args.service_name.clone())
         .with_http_listener(args.metrics)
         .set_buckets(&[
             1e-3, 2e-3, 3e-3, 4e-3, 5e-3, 6e-3, 7e-3, 8e-3, 9e-3, // ms
@@ -138,42 +371,119 @@ async fn main() {
         .install()
         .unwrap();
 
-    let client_builder = Client::builder();
+    let backends = BackendPool::new(args.remote);
 
-    let remote = args.remote;
+    let connector = HttpConnector::new();
 
-    let client =
-        client_builder.build::<_, Body>(HttpConnector::new_with_resolver(Resolver { remote }));
+    let https_connector = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .wrap_connector(connector.clone());
 
-    let state = ProxyState { client, remote };
+    let client = Client::builder()
+        .http2_only(args.upstream_http2 && args.upstream_scheme == UpstreamScheme::Http)
+        .build::<_, Body>(https_connector);
 
-    Server::bind(&args.bind)
-        .serve(
-            Router::new()
-                .fallback(fallback)
-                .with_state(state)
-                .layer(from_fn(observe))
-                .into_make_service(),
-        )
-        .await
-        .unwrap();
-}
+    tokio::spawn(health::run(
+        backends.clone(),
+        Duration::from_secs(args.health_check_interval_secs),
+        Duration::from_millis(args.health_check_timeout_millis),
+    ));
 
-#[derive(Clone)]
-pub struct Resolver {
-    pub remote: SocketAddr,
-}
+    let state = ProxyState {
+        client,
+        connector,
+        backends,
+        proxy_protocol: args.proxy_protocol,
+        upstream_scheme: args.upstream_scheme,
+        upstream_http2: args.upstream_http2,
+        max_body_bytes: args.max_body_bytes,
+        verify_signatures: args.verify_signatures,
+        key_cache: KeyCache::new(KEY_CACHE_TTL),
+    };
+
+    let server = Server::bind(&args.bind).serve(
+        Router::new()
+            .fallback(fallback)
+            .with_state(state.clone())
+            .layer(from_fn_with_state(state, verify_signatures))
+            .layer(from_fn(observe))
+            .into_make_service_with_connect_info::<SocketAddr>(),
+    );
 
-impl Service<Name> for Resolver {
-    type Response = Once<SocketAddr>;
-    type Error = Infallible;
-    type Future = Ready<Result<Self::Response, Self::Error>>;
+    let signalled = std::sync::Arc::new(tokio::sync::Notify::new());
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    let graceful = server.with_graceful_shutdown({
+        let signalled = signalled.clone();
+        async move {
+            shutdown_signal().await;
+            signalled.notify_one();
+        }
+    });
+
+    // The grace period starts counting only once a shutdown signal arrives.
+    let grace = Duration::from_secs(args.shutdown_grace_secs);
+    let deadline = async {
+        signalled.notified().await;
+        tokio::time::sleep(grace).await;
+    };
+
+    let outcome = tokio::select! {
+        result = graceful => Some(result),
+        _ = deadline => None,
+    };
+
+    match outcome {
+        Some(Ok(())) => {
+            tracing::info!(message = "Shutdown complete; all connections drained");
+            counter!("connections_drained", 1);
+        }
+        Some(Err(error)) => {
+            tracing::error!(message = "Server exited with error", ?error);
+        }
+        None => {
+            let remaining = ACTIVE_REQUESTS.load(Ordering::Relaxed);
+
+            tracing::warn!(
+                message = "Shutdown grace period elapsed; forcibly terminating remaining connections",
+                remaining
+            );
+
+            counter!("connections_forcibly_terminated", remaining as u64);
+        }
     }
+}
 
-    fn call(&mut self, _req: Name) -> Self::Future {
-        ready(Ok::<_, Infallible>(once(self.remote)))
+/// Resolves once a SIGINT or SIGTERM is received, triggering hyper's
+/// graceful shutdown so the listener stops accepting new connections while
+/// in-flight requests keep running.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install SIGINT handler");
+    };
+
+    let terminate = async {
+        tokio::signal::unix::signal(SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
+
+    let draining = ACTIVE_REQUESTS.load(Ordering::Relaxed);
+
+    tracing::info!(
+        message = "Shutdown signal received; draining in-flight connections",
+        draining
+    );
+
+    counter!("connections_draining_at_shutdown", draining as u64);
 }
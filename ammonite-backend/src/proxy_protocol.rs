@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// Builds the ASCII PROXY protocol v1 line for a connection proxied from
+/// `src` to `dst`.
+pub fn encode_v1(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        )
+        .into_bytes(),
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        )
+        .into_bytes(),
+        _ => b"PROXY UNKNOWN\r\n".to_vec(),
+    }
+}
+
+/// Builds the binary PROXY protocol v2 header for a connection proxied from
+/// `src` to `dst`.
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(V2_SIGNATURE.len() + 16 + 36);
+    header.extend_from_slice(&V2_SIGNATURE);
+    // Version 2, command PROXY.
+    header.push(0x21);
+
+    match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            header.push(0x11); // AF_INET, STREAM
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            header.push(0x21); // AF_INET6, STREAM
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&src.ip().octets());
+            header.extend_from_slice(&dst.ip().octets());
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        _ => {
+            header.push(0x00); // AF_UNSPEC
+            header.extend_from_slice(&0u16.to_be_bytes());
+        }
+    }
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_v1_tcp4() {
+        let src: SocketAddr = "192.168.1.1:51234".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:80".parse().unwrap();
+
+        assert_eq!(
+            encode_v1(src, dst),
+            b"PROXY TCP4 192.168.1.1 10.0.0.1 51234 80\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn encode_v1_tcp6() {
+        let src: SocketAddr = "[::1]:1234".parse().unwrap();
+        let dst: SocketAddr = "[::2]:80".parse().unwrap();
+
+        assert_eq!(
+            encode_v1(src, dst),
+            b"PROXY TCP6 ::1 ::2 1234 80\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn encode_v1_mixed_families_is_unknown() {
+        let src: SocketAddr = "192.168.1.1:1234".parse().unwrap();
+        let dst: SocketAddr = "[::1]:80".parse().unwrap();
+
+        assert_eq!(encode_v1(src, dst), b"PROXY UNKNOWN\r\n".to_vec());
+    }
+
+    #[test]
+    fn encode_v2_tcp4_layout() {
+        let src: SocketAddr = "192.168.1.1:51234".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:80".parse().unwrap();
+        let header = encode_v2(src, dst);
+
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(header[12], 0x21); // version 2, command PROXY
+        assert_eq!(header[13], 0x11); // AF_INET, STREAM
+        assert_eq!(&header[14..16], &12u16.to_be_bytes());
+        assert_eq!(&header[16..20], &[192, 168, 1, 1]);
+        assert_eq!(&header[20..24], &[10, 0, 0, 1]);
+        assert_eq!(&header[24..26], &51234u16.to_be_bytes());
+        assert_eq!(&header[26..28], &80u16.to_be_bytes());
+        assert_eq!(header.len(), 28);
+    }
+
+    #[test]
+    fn encode_v2_tcp6_layout() {
+        let src: SocketAddr = "[::1]:1234".parse().unwrap();
+        let dst: SocketAddr = "[::2]:80".parse().unwrap();
+        let header = encode_v2(src, dst);
+
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(header[12], 0x21); // version 2, command PROXY
+        assert_eq!(header[13], 0x21); // AF_INET6, STREAM
+        assert_eq!(&header[14..16], &36u16.to_be_bytes());
+        assert_eq!(header.len(), 12 + 1 + 1 + 2 + 36);
+    }
+
+    #[test]
+    fn encode_v2_mixed_families_is_unspec() {
+        let src: SocketAddr = "192.168.1.1:1234".parse().unwrap();
+        let dst: SocketAddr = "[::1]:80".parse().unwrap();
+        let header = encode_v2(src, dst);
+
+        assert_eq!(header[13], 0x00); // AF_UNSPEC
+        assert_eq!(&header[14..16], &0u16.to_be_bytes());
+        assert_eq!(header.len(), 16);
+    }
+}
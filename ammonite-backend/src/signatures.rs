@@ -0,0 +1,597 @@
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::request::Parts;
+use axum::http::{Request, Response, StatusCode};
+use axum::middleware::Next;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use ed25519_dalek::Verifier as _;
+use hyper::body::HttpBody;
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnectorBuilder;
+use metrics::counter;
+use rsa::pkcs1v15::Signature as RsaSignature;
+use rsa::signature::Verifier as _;
+use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::Service;
+
+use crate::state::ProxyState;
+
+/// Applied alongside `observe`: verifies HTTP Signatures on inbound
+/// ActivityPub federation traffic (`/inbox`, `/users/*/inbox`) before it
+/// ever reaches the upstream Mastodon process. Gated behind
+/// `--verify-signatures`; a no-op otherwise.
+pub async fn verify_signatures(
+    State(state): State<ProxyState>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response<axum::body::BoxBody> {
+    if !state.verify_signatures || !is_inbox_path(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+
+    let body_bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            tracing::error!(message = "Failed to buffer inbox request body", ?error);
+            counter!("signature_failures", 1, "reason" => "body_read_error");
+
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap()
+                .map(axum::body::boxed);
+        }
+    };
+
+    if let Err(reason) = verify_request(&parts, &body_bytes, &state.key_cache).await {
+        tracing::warn!(
+            message = "Rejecting inbound ActivityPub request with invalid signature",
+            reason
+        );
+        counter!("signature_failures", 1, "reason" => reason);
+
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::empty())
+            .unwrap()
+            .map(axum::body::boxed);
+    }
+
+    let request = Request::from_parts(parts, Body::from(body_bytes));
+    next.run(request).await
+}
+
+fn is_inbox_path(path: &str) -> bool {
+    path == "/inbox" || (path.starts_with("/users/") && path.ends_with("/inbox"))
+}
+
+async fn verify_request(
+    parts: &Parts,
+    body: &[u8],
+    key_cache: &KeyCache,
+) -> Result<(), &'static str> {
+    let signature_header = parts
+        .headers
+        .get("signature")
+        .and_then(|value| value.to_str().ok())
+        .ok_or("missing_signature_header")?;
+
+    let fields = parse_signature_header(signature_header).ok_or("malformed_signature_header")?;
+
+    let key_id = fields.get("keyId").ok_or("missing_key_id")?;
+    let signature = fields.get("signature").ok_or("missing_signature")?;
+    let covered_headers = fields
+        .get("headers")
+        .map(String::as_str)
+        .unwrap_or("(request-target) host date");
+
+    // A signature whose `headers` field doesn't cover `Digest` never binds
+    // the body to the signature at all: the `Digest` header and the body it
+    // describes could both be swapped out by an attacker and the signature
+    // would still verify against the unchanged method/host/date. Require it
+    // to be covered whenever there's a body to protect.
+    if !body.is_empty()
+        && !covered_headers
+            .split_whitespace()
+            .any(|name| name.eq_ignore_ascii_case("digest"))
+    {
+        return Err("digest_not_signed");
+    }
+
+    if !body.is_empty() {
+        verify_digest(parts, body)?;
+    }
+
+    let signing_string = build_signing_string(parts, covered_headers, &fields)?;
+
+    let signature_bytes = BASE64
+        .decode(signature)
+        .map_err(|_| "malformed_signature_encoding")?;
+
+    let public_key_pem = key_cache
+        .get_or_fetch(key_id)
+        .await
+        .map_err(|_| "key_fetch_failed")?;
+
+    let public_key = parse_public_key(&public_key_pem).ok_or("unsupported_key")?;
+
+    if verify_signature(&public_key, signing_string.as_bytes(), &signature_bytes) {
+        Ok(())
+    } else {
+        Err("invalid_signature")
+    }
+}
+
+/// Validates the `Digest` header (`SHA-256=<base64>`) against the buffered
+/// request body. A missing `Digest` header, or one using any algorithm other
+/// than SHA-256, is rejected.
+fn verify_digest(parts: &Parts, body: &[u8]) -> Result<(), &'static str> {
+    let Some(digest_header) = parts
+        .headers
+        .get("digest")
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Err("missing_digest");
+    };
+
+    let Some(encoded) = digest_header.strip_prefix("SHA-256=") else {
+        return Err("unsupported_digest_algorithm");
+    };
+
+    let expected = BASE64.decode(encoded).map_err(|_| "malformed_digest")?;
+    let actual = Sha256::digest(body);
+
+    if actual.as_slice() == expected.as_slice() {
+        Ok(())
+    } else {
+        Err("digest_mismatch")
+    }
+}
+
+/// Reconstructs the signing string by concatenating, in the order given by
+/// the signature's `headers` field, each pseudo-header or real header value
+/// as `"{name}: {value}"` joined by newlines. `(request-target)` is derived
+/// from the request line; `(created)`/`(expires)`, used by `hs2019`
+/// signatures, come from the matching parameter on the `Signature` header
+/// itself rather than an HTTP header of the same name.
+fn build_signing_string(
+    parts: &Parts,
+    covered_headers: &str,
+    fields: &HashMap<String, String>,
+) -> Result<String, &'static str> {
+    let mut lines = Vec::new();
+
+    for name in covered_headers.split_whitespace() {
+        let line = if name.eq_ignore_ascii_case("(request-target)") {
+            let path_and_query = parts
+                .uri
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+
+            format!(
+                "(request-target): {} {path_and_query}",
+                parts.method.as_str().to_lowercase()
+            )
+        } else if name.eq_ignore_ascii_case("(created)") {
+            let created = fields.get("created").ok_or("missing_created_param")?;
+            format!("(created): {created}")
+        } else if name.eq_ignore_ascii_case("(expires)") {
+            let expires = fields.get("expires").ok_or("missing_expires_param")?;
+            format!("(expires): {expires}")
+        } else {
+            let value = parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or("missing_covered_header")?;
+
+            format!("{}: {value}", name.to_lowercase())
+        };
+
+        lines.push(line);
+    }
+
+    Ok(lines.join("\n"))
+}
+
+fn parse_signature_header(value: &str) -> Option<HashMap<String, String>> {
+    let mut fields = HashMap::new();
+
+    for part in value.split(',') {
+        let part = part.trim();
+
+        if part.is_empty() {
+            continue;
+        }
+
+        let (key, value) = part.split_once('=')?;
+        fields.insert(key.trim().to_string(), value.trim().trim_matches('"').to_string());
+    }
+
+    Some(fields)
+}
+
+enum PublicKey {
+    Rsa(rsa::RsaPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+fn parse_public_key(pem: &str) -> Option<PublicKey> {
+    use rsa::pkcs8::DecodePublicKey as _;
+
+    if let Ok(key) = rsa::RsaPublicKey::from_public_key_pem(pem) {
+        return Some(PublicKey::Rsa(key));
+    }
+
+    if let Ok(key) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+        return Some(PublicKey::Ed25519(key));
+    }
+
+    None
+}
+
+fn verify_signature(key: &PublicKey, signing_string: &[u8], signature: &[u8]) -> bool {
+    match key {
+        PublicKey::Rsa(key) => {
+            let verifying_key = rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key.clone());
+
+            match RsaSignature::try_from(signature) {
+                Ok(signature) => verifying_key.verify(signing_string, &signature).is_ok(),
+                Err(_) => false,
+            }
+        }
+        PublicKey::Ed25519(key) => match ed25519_dalek::Signature::try_from(signature) {
+            Ok(signature) => key.verify(signing_string, &signature).is_ok(),
+            Err(_) => false,
+        },
+    }
+}
+
+/// How long to wait for an actor key fetch (DNS + connect + response) before
+/// giving up, so a slow or non-responding `keyId` host can't tie up a task
+/// indefinitely.
+const KEY_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest actor document accepted from a `keyId` fetch. Actor objects are a
+/// few KB in practice; this just bounds how much a malicious key server can
+/// make this proxy buffer.
+const MAX_KEY_RESPONSE_BYTES: usize = 64 * 1024;
+
+struct CachedKey {
+    pem: String,
+    fetched_at: Instant,
+}
+
+/// Caches actor public keys fetched by dereferencing a signature's `keyId`,
+/// bounded by a TTL so a burst of federation traffic from the same actor
+/// doesn't cause a refetch storm, and single-flighted per `key_id` so N
+/// concurrent deliveries from the same actor before the key is cached
+/// collapse into one outbound fetch rather than N.
+#[derive(Clone)]
+pub struct KeyCache {
+    cache: Arc<Mutex<HashMap<String, CachedKey>>>,
+    in_flight: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    ttl: Duration,
+}
+
+impl KeyCache {
+    pub fn new(ttl: Duration) -> Self {
+        KeyCache {
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    async fn get_or_fetch(&self, key_id: &str) -> Result<String, ()> {
+        if let Some(pem) = self.cached(key_id) {
+            return Ok(pem);
+        }
+
+        let lock = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key_id.to_string())
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())))
+            .clone();
+
+        let _guard = lock.lock().await;
+
+        // Another caller may have populated the cache while we waited.
+        if let Some(pem) = self.cached(key_id) {
+            self.in_flight.lock().unwrap().remove(key_id);
+            return Ok(pem);
+        }
+
+        let result = self.fetch(key_id).await;
+
+        self.in_flight.lock().unwrap().remove(key_id);
+
+        let pem = result?;
+
+        self.cache.lock().unwrap().insert(
+            key_id.to_string(),
+            CachedKey {
+                pem: pem.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+
+        Ok(pem)
+    }
+
+    fn cached(&self, key_id: &str) -> Option<String> {
+        let cache = self.cache.lock().unwrap();
+        let entry = cache.get(key_id)?;
+
+        if entry.fetched_at.elapsed() < self.ttl {
+            Some(entry.pem.clone())
+        } else {
+            None
+        }
+    }
+
+    async fn fetch(&self, key_id: &str) -> Result<String, ()> {
+        match tokio::time::timeout(KEY_FETCH_TIMEOUT, self.fetch_inner(key_id)).await {
+            Ok(result) => result,
+            Err(_) => Err(()),
+        }
+    }
+
+    /// `keyId` is taken verbatim from an unauthenticated `Signature` header,
+    /// so an attacker can put anything there. Requiring `https` and
+    /// rejecting private/loopback addresses isn't enough on its own: a
+    /// connector that re-resolves `host` when it actually connects can be
+    /// handed a different answer than the one just validated (an attacker
+    /// controlling DNS for the `keyId` host, or a rebinding/round-robin
+    /// record, can return a public address for this lookup and a private
+    /// one a moment later). So resolution happens exactly once here, the
+    /// resolved addresses are validated, and the connector is pinned to
+    /// connect only to those addresses — it never gets to resolve `host`
+    /// itself.
+    async fn fetch_inner(&self, key_id: &str) -> Result<String, ()> {
+        let uri: hyper::Uri = key_id.parse().map_err(|_| ())?;
+
+        if uri.scheme_str() != Some("https") {
+            return Err(());
+        }
+
+        let host = uri.host().ok_or(())?;
+        let port = uri.port_u16().unwrap_or(443);
+
+        let addrs = resolve_validated_addrs(host, port).await?;
+
+        let connector = HttpConnector::new_with_resolver(PinnedResolver { addrs });
+        let https = HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_only()
+            .enable_http1()
+            .wrap_connector(connector);
+        let client = hyper::Client::builder().build::<_, Body>(https);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri(uri)
+            .header("accept", "application/activity+json")
+            .body(Body::empty())
+            .map_err(|_| ())?;
+
+        let response = client.request(request).await.map_err(|_| ())?;
+        let body = read_body_capped(response.into_body(), MAX_KEY_RESPONSE_BYTES).await?;
+
+        let actor: serde_json::Value = serde_json::from_slice(&body).map_err(|_| ())?;
+
+        actor
+            .get("publicKey")
+            .and_then(|key| key.get("publicKeyPem"))
+            .and_then(|pem| pem.as_str())
+            .map(str::to_string)
+            .ok_or(())
+    }
+}
+
+/// Resolves `host` exactly once and rejects it outright if it's (or
+/// resolves to) an address `is_blocked_host_ip` rejects. The caller must connect to one of the returned addresses
+/// rather than resolving `host` again, or this check can be bypassed by a
+/// DNS answer that changes between the two lookups.
+async fn resolve_validated_addrs(host: &str, port: u16) -> Result<Vec<SocketAddr>, ()> {
+    let addrs: Vec<SocketAddr> = if let Ok(ip) = host.parse::<IpAddr>() {
+        vec![SocketAddr::new(ip, port)]
+    } else {
+        tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| ())?
+            .collect()
+    };
+
+    if addrs.is_empty() || addrs.iter().any(|addr| is_blocked_host_ip(addr.ip())) {
+        return Err(());
+    }
+
+    Ok(addrs)
+}
+
+/// A DNS resolver that always returns a fixed, pre-validated address set
+/// regardless of the name asked for. Used to pin a one-off `HttpConnector`
+/// to the addresses `resolve_validated_addrs` already checked, so the
+/// connector can't independently re-resolve the `keyId` host.
+#[derive(Clone)]
+struct PinnedResolver {
+    addrs: Vec<SocketAddr>,
+}
+
+impl Service<Name> for PinnedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+        std::future::ready(Ok(self.addrs.clone().into_iter()))
+    }
+}
+
+/// Rejects IPv4 addresses that are loopback, private, link-local,
+/// unspecified, broadcast or documentation, and IPv6 addresses that are
+/// loopback, unspecified, multicast, unique local or link-local. IPv4-mapped
+/// IPv6 addresses (`::ffff:169.254.169.254`) are normalized to their IPv4
+/// form first, so they're checked against the same rules rather than
+/// slipping past the IPv6-only checks below; other IPv6 forms that embed an
+/// IPv4 address are checked as IPv6 only.
+fn is_blocked_host_ip(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+        }
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (v6.segments()[0] & 0xfe00) == 0xfc00 // unique local fc00::/7
+                || (v6.segments()[0] & 0xffc0) == 0xfe80 // link-local fe80::/10
+        }
+    }
+}
+
+/// Reads a response body up to `cap` bytes, erroring instead of buffering an
+/// unbounded or slow-drip payload from a malicious key server.
+async fn read_body_capped(mut body: Body, cap: usize) -> Result<Bytes, ()> {
+    let mut buf = BytesMut::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| ())?;
+
+        if buf.len() + chunk.len() > cap {
+            return Err(());
+        }
+
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    #[test]
+    fn is_inbox_path_matches_shared_and_per_user_inbox() {
+        assert!(is_inbox_path("/inbox"));
+        assert!(is_inbox_path("/users/alice/inbox"));
+        assert!(!is_inbox_path("/users/alice/outbox"));
+        assert!(!is_inbox_path("/"));
+    }
+
+    #[test]
+    fn parse_signature_header_splits_quoted_fields() {
+        let fields = parse_signature_header(
+            r#"keyId="https://example.com/actor#main-key",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="abc123=""#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fields.get("keyId").unwrap(),
+            "https://example.com/actor#main-key"
+        );
+        assert_eq!(
+            fields.get("headers").unwrap(),
+            "(request-target) host date digest"
+        );
+        assert_eq!(fields.get("signature").unwrap(), "abc123=");
+    }
+
+    #[test]
+    fn build_signing_string_covers_request_target_and_headers() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/inbox")
+            .header("host", "example.com")
+            .header("date", "Wed, 30 Jul 2026 00:00:00 GMT")
+            .body(())
+            .unwrap();
+        let (parts, _) = request.into_parts();
+
+        let signing_string =
+            build_signing_string(&parts, "(request-target) host date", &HashMap::new()).unwrap();
+
+        assert_eq!(
+            signing_string,
+            "(request-target): post /inbox\nhost: example.com\ndate: Wed, 30 Jul 2026 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn build_signing_string_covers_created_and_expires_from_signature_params() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/inbox")
+            .body(())
+            .unwrap();
+        let (parts, _) = request.into_parts();
+
+        let mut fields = HashMap::new();
+        fields.insert("created".to_string(), "1690000000".to_string());
+        fields.insert("expires".to_string(), "1690000300".to_string());
+
+        let signing_string =
+            build_signing_string(&parts, "(created) (expires)", &fields).unwrap();
+
+        assert_eq!(
+            signing_string,
+            "(created): 1690000000\n(expires): 1690000300"
+        );
+    }
+
+    #[test]
+    fn build_signing_string_rejects_unknown_covered_header() {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/inbox")
+            .body(())
+            .unwrap();
+        let (parts, _) = request.into_parts();
+
+        assert_eq!(
+            build_signing_string(&parts, "x-not-sent", &HashMap::new()),
+            Err("missing_covered_header")
+        );
+    }
+
+    #[test]
+    fn is_blocked_host_ip_rejects_loopback_private_and_link_local() {
+        assert!(is_blocked_host_ip("127.0.0.1".parse().unwrap()));
+        assert!(is_blocked_host_ip("169.254.169.254".parse().unwrap()));
+        assert!(is_blocked_host_ip("10.0.0.1".parse().unwrap()));
+        assert!(is_blocked_host_ip("::1".parse().unwrap()));
+        assert!(!is_blocked_host_ip("93.184.216.34".parse().unwrap()));
+    }
+
+    #[test]
+    fn is_blocked_host_ip_unwraps_ipv4_mapped_addresses() {
+        assert!(is_blocked_host_ip("::ffff:169.254.169.254".parse().unwrap()));
+        assert!(is_blocked_host_ip("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!is_blocked_host_ip("::ffff:93.184.216.34".parse().unwrap()));
+    }
+}
@@ -1,9 +1,32 @@
-use crate::Resolver;
+use crate::backends::BackendPool;
+use crate::cli::{ProxyProtocol, UpstreamScheme};
+use crate::signatures::KeyCache;
 use hyper::client::HttpConnector;
-use std::net::SocketAddr;
+use hyper_rustls::HttpsConnector;
 
 #[derive(Clone)]
 pub struct ProxyState {
-    pub client: hyper::Client<HttpConnector<Resolver>>,
-    pub remote: SocketAddr,
+    /// Transparently speaks plain HTTP or TLS depending on the request URI's
+    /// scheme; TLS connections always advertise h2 alongside http/1.1 via
+    /// ALPN. The destination
+    /// backend is chosen per-request from `backends` and written directly
+    /// into the request URI's authority, so no custom DNS resolution is
+    /// needed here.
+    pub client: hyper::Client<HttpsConnector<HttpConnector>>,
+    /// Bare connector used to hand-roll connections when a PROXY protocol
+    /// header needs to be written before the HTTP handshake, since the
+    /// pooled `client` above hides the raw stream. Only used for plain-HTTP
+    /// upstreams.
+    pub connector: HttpConnector,
+    pub backends: BackendPool,
+    pub proxy_protocol: ProxyProtocol,
+    pub upstream_scheme: UpstreamScheme,
+    /// Mirrors `--upstream-http2`. The pooled `client` above is built with it
+    /// already applied as prior-knowledge h2 for plain-HTTP upstreams;
+    /// `send_with_proxy_protocol` reads this field to honor the same setting
+    /// on its hand-rolled handshake.
+    pub upstream_http2: bool,
+    pub max_body_bytes: u64,
+    pub verify_signatures: bool,
+    pub key_cache: KeyCache,
 }
@@ -0,0 +1,45 @@
+use crate::cli::Args;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{trace, Resource};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Initializes `tracing_subscriber`, bridging it into an OTLP exporter
+/// pipeline via `tracing-opentelemetry` when `--otlp-endpoint` is set so
+/// spans are exported alongside the existing formatted log output.
+pub fn init(args: &Args) {
+    let fmt_layer = tracing_subscriber::fmt::layer();
+
+    match &args.otlp_endpoint {
+        Some(endpoint) => {
+            opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    opentelemetry::KeyValue::new("service.name", args.service_name.clone()),
+                ])))
+                .install_batch(opentelemetry_sdk::runtime::Tokio)
+                .expect("failed to install OTLP tracer");
+
+            tracing_subscriber::registry()
+                .with(LevelFilter::INFO)
+                .with(fmt_layer)
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init();
+        }
+        None => {
+            tracing_subscriber::registry()
+                .with(LevelFilter::INFO)
+                .with(fmt_layer)
+                .init();
+        }
+    }
+}